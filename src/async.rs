@@ -1,8 +1,11 @@
 #![allow(async_fn_in_trait)]
 use super::forward::*;
+use super::codec::{Bincode, Codec, Rkyv, RkyvRef};
+use super::sync::DatagramTooLarge;
 
 use std::{
     io,
+    marker::PhantomData,
     net::{
         SocketAddrV4,
         SocketAddr,
@@ -16,7 +19,8 @@ use tokio::{
     net::{
         ToSocketAddrs,
         TcpListener,
-        TcpStream
+        TcpStream,
+        UdpSocket
     },
     io::{AsyncReadExt, AsyncWriteExt},
     task::spawn,
@@ -24,35 +28,53 @@ use tokio::{
 };
 use igd::{aio::search_gateway, PortMappingProtocol, SearchOptions};
 use local_ip_address::local_ip;
-use serde::{de::{DeserializeOwned, Deserialize}, Serialize};
+use serde::{de::DeserializeOwned, Serialize};
 use bincode;
 
-pub struct AsyncMessageStream {
+/// Parameterized by a `Codec` controlling how payloads are encoded; defaults to `Bincode`,
+/// matching the wire format `netu` has always used.
+pub struct AsyncMessageStream<C = Bincode> {
     inner: TcpStream,
     offset: usize,
     buffer: Vec<u8>,
-    referenced: bool
+    referenced: bool,
+    codec: PhantomData<C>
 }
 
-impl AsyncMessageStream {
+impl AsyncMessageStream<Bincode> {
+    fn from_stream(stream: TcpStream) -> Self {
+        AsyncMessageStream {
+            inner: stream,
+            offset: 0,
+            buffer: vec![0u8; 1024],
+            referenced: false,
+            codec: PhantomData
+        }
+    }
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
-        TcpStream::connect(addr).await.map(|stream| {
-            AsyncMessageStream {
-                inner: stream,
-                offset: 0,
-                buffer: vec![0u8; 1024],
-                referenced: false
-            }
-        })
+        TcpStream::connect(addr).await.map(Self::from_stream)
     }
-    pub async fn send<M: Serialize>(&mut self, message: M) -> Result<(), Box<dyn Error>> {
-        let raw = bincode::serialize(&message)?;
+}
+
+impl<C> AsyncMessageStream<C> {
+    /// Swaps the `Codec` this stream uses, e.g. `AsyncMessageStream::connect(addr).await?.with_codec::<Rkyv>()`.
+    pub fn with_codec<C2>(self) -> AsyncMessageStream<C2> {
+        AsyncMessageStream {
+            inner: self.inner,
+            offset: self.offset,
+            buffer: self.buffer,
+            referenced: self.referenced,
+            codec: PhantomData
+        }
+    }
+    pub async fn send<M>(&mut self, message: M) -> Result<(), Box<dyn Error>> where C: Codec<M> {
+        let raw = C::encode(&message).map_err(|err| Box::new(err) as Box<dyn Error>)?;
         let header = (8 + raw.len() as u64).to_be_bytes();
         self.inner.write_all(&header).await?;
         self.inner.write_all(&raw).await?;
         Ok(())
     }
-    pub async fn recv<M: DeserializeOwned>(&mut self) -> Result<Option<M>, Box<dyn Error>> {
+    pub async fn recv<M>(&mut self) -> Result<Option<M>, Box<dyn Error>> where C: Codec<M> {
         if self.referenced {
             let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
             self.buffer.drain(0..size);
@@ -75,7 +97,7 @@ impl AsyncMessageStream {
         if self.offset > 8 {
             let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
             if self.offset >= size {
-                let message: M = bincode::deserialize(&self.buffer[8..size])?;
+                let message: M = C::decode(&self.buffer[8..size]).map_err(|err| Box::new(err) as Box<dyn Error>)?;
                 self.buffer.drain(0..size);
                 self.offset -= size;
                 return Ok(Some(message));
@@ -84,7 +106,13 @@ impl AsyncMessageStream {
 
         Ok(None)
     }
-    pub async fn recv_ref<'a, M: Deserialize<'a>>(&'a mut self) -> Result<Option<M>, Box<dyn Error>> {
+}
+
+impl AsyncMessageStream<Rkyv> {
+    /// Receive a type validated in place against the internal buffer, with no allocation or copy.
+    /// The returned reference borrows `self`: it stays valid until the next `recv`/`recv_ref` call,
+    /// which is what drops and replaces the bytes it points into.
+    pub async fn recv_ref<M: RkyvRef>(&mut self) -> Result<Option<&rkyv::Archived<M>>, Box<dyn Error>> {
         if self.referenced {
             let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
             self.buffer.drain(0..size);
@@ -107,9 +135,10 @@ impl AsyncMessageStream {
         if self.offset > 8 {
             let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
             if self.offset >= size {
-                let message: M = bincode::deserialize(&self.buffer[8..size])?;
+                let archived = rkyv::check_archived_root::<M>(&self.buffer[8..size])
+                    .map_err(|_| Box::new(RkyvValidationError) as Box<dyn Error>)?;
                 self.referenced = true;
-                return Ok(Some(message));
+                return Ok(Some(archived));
             }
         }
 
@@ -117,6 +146,17 @@ impl AsyncMessageStream {
     }
 }
 
+#[derive(Debug)]
+struct RkyvValidationError;
+
+impl std::fmt::Display for RkyvValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "received payload failed rkyv validation")
+    }
+}
+
+impl Error for RkyvValidationError {}
+
 pub trait AsyncTcpListenerExt {
     async fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> where Self: Sized;
     async fn messenger(&self) -> io::Result<(AsyncMessageStream, SocketAddr)>;
@@ -126,7 +166,7 @@ impl AsyncTcpListenerExt for TcpListener {
     /// Works the same as `TcpListener::bind` but also spawns a task that periodically requests router to uPnP forward specified port.
     async fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> {
         let listener = TcpListener::bind(addr).await?;
-        let (external, state) = forward(listener.local_addr().unwrap().port()).await?;
+        let (external, state) = forward(listener.local_addr().unwrap().port(), PortMappingProtocol::TCP).await?;
         Ok(Forwarded {
             inner: listener,
             external,
@@ -135,19 +175,83 @@ impl AsyncTcpListenerExt for TcpListener {
     }
     /// Works the same as `TcpListener::accept` but returns a `MessageStream` instead of `TcpStream`.
     async fn messenger(&self) -> io::Result<(AsyncMessageStream, SocketAddr)> {
-        self.accept().await.map(|(stream, addr)| (
-            AsyncMessageStream {
-                inner: stream,
-                offset: 0,
-                buffer: vec![0u8; 1024],
-                referenced: false
-            },
-            addr
-        ))
+        self.accept().await.map(|(stream, addr)| (AsyncMessageStream::from_stream(stream), addr))
+    }
+}
+
+pub trait AsyncUdpSocketExt {
+    async fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> where Self: Sized;
+}
+
+impl AsyncUdpSocketExt for UdpSocket {
+    /// Works the same as `UdpSocket::bind` but also spawns a task that periodically requests router to uPnP forward specified port.
+    async fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> {
+        let socket = UdpSocket::bind(addr).await?;
+        let (external, state) = forward(socket.local_addr().unwrap().port(), PortMappingProtocol::UDP).await?;
+        Ok(Forwarded {
+            inner: socket,
+            external,
+            state
+        })
     }
 }
 
-async fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dyn Error>>  {
+/// An async wrapper for `UdpSocket` that allows to simply send and receive structs which implement `serde::{Serialize, Deserialize}`.
+/// Unlike `AsyncMessageStream` there is no internal buffering across calls: each datagram carries exactly one framed message.
+/// The receive buffer is a fixed 1024 bytes and never grows, so an encoded message (8-byte header
+/// included) larger than that can never be received - see `recv`.
+pub struct AsyncMessageDatagram {
+    inner: UdpSocket,
+    buffer: Vec<u8>
+}
+
+impl AsyncMessageDatagram {
+    /// Binds a socket and connects it to `addr`, so `send`/`recv` can be used without specifying a peer on every call.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        Ok(AsyncMessageDatagram {
+            inner: socket,
+            buffer: vec![0u8; 1024]
+        })
+    }
+    /// Send a type that implements `serde::Serialize` as a single datagram. `message` must encode,
+    /// header included, to 1024 bytes or less - `recv` can't ever deliver anything larger.
+    pub async fn send<M: Serialize>(&mut self, message: M) -> Result<(), Box<dyn Error>> {
+        let raw = bincode::serialize(&message)?;
+        let mut packet = (8 + raw.len() as u64).to_be_bytes().to_vec();
+        packet.extend_from_slice(&raw);
+        self.inner.send(&packet).await?;
+        Ok(())
+    }
+    /// Receive a type that implements `serde::Deserialize`.
+    /// Returns `Err(DatagramTooLarge)` if a datagram's length header claims more bytes than were
+    /// received (as for any message over the 1024-byte buffer), rather than silently dropping it
+    /// as if nothing had arrived.
+    pub async fn recv<M: DeserializeOwned>(&mut self) -> Result<Option<M>, Box<dyn Error>> {
+        let n = self.inner.recv(&mut self.buffer).await?;
+
+        if n < 8 {
+            return Ok(None);
+        }
+
+        // A malformed/garbage datagram (e.g. a bare probe on the newly-forwarded port) can claim
+        // any length in its header; reject anything that doesn't actually fit what we received
+        // instead of indexing blindly.
+        let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
+        if size < 8 {
+            return Ok(None);
+        }
+        if size > n {
+            return Err(Box::new(DatagramTooLarge));
+        }
+
+        let message: M = bincode::deserialize(&self.buffer[8..size])?;
+        Ok(Some(message))
+    }
+}
+
+async fn forward(port: u16, protocol: PortMappingProtocol) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dyn Error>>  {
     let ip = local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
 
     let gateway = match search_gateway(SearchOptions::default()).await {
@@ -170,7 +274,7 @@ async fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>),
 
     gateway
     .add_port(
-        PortMappingProtocol::TCP,
+        protocol,
         port,
         SocketAddrV4::new(ip, port),
         LEASE.as_secs() as u32 + 1,
@@ -186,7 +290,7 @@ async fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>),
             loop {
                 if let Err(_err) = gateway
                     .add_port(
-                        PortMappingProtocol::TCP,
+                        protocol,
                         port,
                         SocketAddrV4::new(ip, port),
                         LEASE.as_secs() as u32 + 1,
@@ -198,7 +302,7 @@ async fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>),
                     *state.0.lock().unwrap() = State::Error;
                     break
                 }
-                
+
                 let (lock, cv) = &*state;
                 let (guard, wait) = cv.wait_timeout_while(
                     lock.lock().unwrap(),
@@ -210,7 +314,7 @@ async fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>),
                     break;
                 }
             }
-            let _ = gateway.remove_port(PortMappingProtocol::TCP, port).await;
+            let _ = gateway.remove_port(protocol, port).await;
         }
     });
 
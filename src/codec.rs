@@ -0,0 +1,83 @@
+use std::error::Error;
+
+use rkyv::{ser::serializers::AllocSerializer, AlignedVec, Archive, Deserialize as RkyvDeserialize, Infallible, Serialize as RkyvSerialize};
+use rkyv::validation::validators::DefaultValidator;
+use bytecheck::CheckBytes;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Pluggable wire-format for `MessageStream`/`AsyncMessageStream`. The framing (an 8-byte
+/// big-endian total length prefix) is fixed; a `Codec` only controls how the payload itself,
+/// i.e. the bytes after that prefix, is encoded and decoded.
+pub trait Codec<M> {
+    type Error: Error + 'static;
+
+    fn encode(message: &M) -> Result<Vec<u8>, Self::Error>;
+    fn decode(bytes: &[u8]) -> Result<M, Self::Error>;
+}
+
+/// The default codec, matching the wire format `netu` has always used.
+pub struct Bincode;
+
+impl<M: Serialize + DeserializeOwned> Codec<M> for Bincode {
+    type Error = bincode::Error;
+
+    fn encode(message: &M) -> Result<Vec<u8>, Self::Error> {
+        bincode::serialize(message)
+    }
+    fn decode(bytes: &[u8]) -> Result<M, Self::Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
+/// A zero-copy codec backed by `rkyv`. `send`/`recv` still round-trip through an owned value, but
+/// `MessageStream<Rkyv>::recv_ref`/`AsyncMessageStream<Rkyv>::recv_ref` validate the payload in
+/// place and hand back a reference into the stream's internal buffer, with no allocation or copy.
+/// See their docs for the invariant that comes with borrowing from the stream directly.
+pub struct Rkyv;
+
+impl<M> Codec<M> for Rkyv
+where
+    M: Archive + RkyvSerialize<AllocSerializer<256>>,
+    M::Archived: RkyvDeserialize<M, Infallible> + for<'a> CheckBytes<DefaultValidator<'a>>
+{
+    type Error = RkyvCodecError;
+
+    fn encode(message: &M) -> Result<Vec<u8>, Self::Error> {
+        let bytes: AlignedVec = rkyv::to_bytes::<_, 256>(message).map_err(RkyvCodecError::Encode)?;
+        Ok(bytes.into_vec())
+    }
+    fn decode(bytes: &[u8]) -> Result<M, Self::Error> {
+        // `bytes` comes straight off the wire, so it must be validated before it's trusted,
+        // the same as `recv_ref` does - `archived_root`'s unchecked accessor would be UB here.
+        let archived = rkyv::check_archived_root::<M>(bytes).map_err(|_| RkyvCodecError::Validation)?;
+        Ok(archived.deserialize(&mut Infallible).unwrap())
+    }
+}
+
+/// Error returned by `Rkyv`'s `Codec` impl.
+#[derive(Debug)]
+pub enum RkyvCodecError {
+    Encode(rkyv::ser::serializers::AllocSerializerError),
+    Validation
+}
+
+impl std::fmt::Display for RkyvCodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for RkyvCodecError {}
+
+/// Bound satisfied by any `M` that `MessageStream<Rkyv>::recv_ref`/`AsyncMessageStream<Rkyv>::recv_ref`
+/// can validate in place, i.e. any `Archive` type whose archived form implements `CheckBytes`.
+pub trait RkyvRef: Archive
+where
+    Self::Archived: for<'a> CheckBytes<DefaultValidator<'a>>
+{}
+
+impl<M> RkyvRef for M
+where
+    M: Archive,
+    M::Archived: for<'a> CheckBytes<DefaultValidator<'a>>
+{}
@@ -0,0 +1,129 @@
+use super::sync::MessageStream;
+
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+
+/// Identifies which pair of peers the rendezvous server should match up.
+pub type PeerToken = u64;
+
+/// Given to the rendezvous server together with our nonce. It reports back our peer's observed
+/// external address and nonce once both sides of the `peer_token` have checked in.
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    token: PeerToken,
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerInfo {
+    addr: SocketAddrV4,
+    nonce: u64,
+}
+
+#[derive(Debug)]
+pub enum HolePunchError {
+    RendezvousFailed,
+    ConnectFailed,
+}
+
+impl std::fmt::Display for HolePunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for HolePunchError {}
+
+/// Overall time budget both sides spend retrying the simultaneous connect below before giving up
+/// and retrying the whole rendezvous with a fresh nonce.
+const CONNECT_DEADLINE: Duration = Duration::from_secs(5);
+
+/// How long a single connect attempt blocks before it's retried with a fresh socket. Short enough
+/// that the peer's NAT mapping opened by our own outbound SYN has time to let a later attempt
+/// through without burning the whole `CONNECT_DEADLINE` on one try.
+const CONNECT_RETRY: Duration = Duration::from_millis(500);
+
+fn bind_reusable(port: u16) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket)
+}
+
+/// Performs a libp2p-style simultaneous TCP open with whoever else calls `hole_punch` with the same
+/// `peer_token` against `rendezvous`. Both peers bind the same local port with
+/// `SO_REUSEADDR`/`SO_REUSEPORT`, exchange a random nonce through the rendezvous server, then both
+/// dial the other's observed external address from that port: each side's own outbound SYN is what
+/// opens its NAT's pinhole, so only having one side ever send a packet (e.g. the other just
+/// listening) fails against any NAT that isn't full-cone. Ties are retried with fresh nonces.
+/// Returns a connected `MessageStream` on success, giving a direct connection when `forward`
+/// fails because the router blocks uPnP.
+pub fn hole_punch<A: ToSocketAddrs>(rendezvous: A, peer_token: PeerToken) -> Result<MessageStream, Box<dyn Error>> {
+    loop {
+        let nonce: u64 = rand::thread_rng().gen();
+
+        let socket = bind_reusable(0)?;
+        let port = socket.local_addr()?.as_socket().unwrap().port();
+
+        socket.connect(&rendezvous_addr(&rendezvous)?.into())
+            .map_err(|_| HolePunchError::RendezvousFailed)?;
+        let control_stream: TcpStream = socket.into();
+        control_stream.set_nonblocking(true)?;
+        let mut control = MessageStream::from_stream(control_stream);
+
+        control.send(Hello { token: peer_token, nonce })?;
+        let peer: PeerInfo = loop {
+            match control.recv()? {
+                Some(info) => break info,
+                None => sleep(Duration::from_millis(50)),
+            }
+        };
+        drop(control);
+
+        if peer.nonce == nonce {
+            log::warn!("hole punch nonce collision, retrying");
+            continue;
+        }
+
+        let stream = match connect_simultaneous(port, SocketAddr::V4(peer.addr)) {
+            Ok(stream) => stream,
+            Err(_) => {
+                log::warn!("hole punch connect to {} failed, retrying with a fresh nonce", peer.addr);
+                continue;
+            }
+        };
+
+        stream.set_nonblocking(true)?;
+        return Ok(MessageStream::from_stream(stream));
+    }
+}
+
+/// Repeatedly dials `peer_addr` from `port` until it connects or `CONNECT_DEADLINE` runs out.
+/// Rebinds a fresh socket to `port` for every attempt, since a socket left over from a failed
+/// connect can't reliably be reused for another one.
+fn connect_simultaneous(port: u16, peer_addr: SocketAddr) -> Result<TcpStream, Box<dyn Error>> {
+    let deadline = Instant::now() + CONNECT_DEADLINE;
+    loop {
+        let socket = bind_reusable(port)?;
+        match socket.connect_timeout(&peer_addr.into(), CONNECT_RETRY) {
+            Ok(()) => return Ok(socket.into()),
+            Err(_) if Instant::now() < deadline => continue,
+            Err(_) => return Err(Box::new(HolePunchError::ConnectFailed)),
+        }
+    }
+}
+
+fn rendezvous_addr<A: ToSocketAddrs>(addr: &A) -> std::io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no addresses to connect to")
+    })
+}
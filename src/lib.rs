@@ -1,16 +1,28 @@
 pub mod forward;
 
+pub mod codec;
+
 pub mod sync;
 #[cfg(feature = "async")]
 pub mod r#async;
 
+pub mod hole_punch;
+
+pub mod relay;
+
 pub mod prelude {
     use super::*;
 
     pub use forward::*;
 
+    pub use codec::*;
+
     pub use sync::*;
 
     #[cfg(feature = "async")]
     pub use r#async::*;
+
+    pub use hole_punch::*;
+
+    pub use relay::*;
 }
\ No newline at end of file
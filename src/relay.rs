@@ -0,0 +1,221 @@
+use super::forward::*;
+use super::sync::MessageStream;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc, Condvar, Mutex},
+    thread::{sleep, spawn},
+    time::Duration,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// The first message sent over any connection opened to a relay: either a request for a new
+/// public port, or a data channel identifying which external connection it carries. `Data`'s
+/// `token` must match the one the relay handed the owning `Control` connection in `Assigned`, so
+/// a connection id - a small, easily-guessed sequential integer - can't be hijacked by a third
+/// party racing the real client to claim it.
+#[derive(Serialize, Deserialize)]
+enum RelayRequest {
+    Control,
+    Data { id: u64, token: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+enum RelayReply {
+    Assigned { external: Ipv4Addr, port: u16, token: u64 },
+    Connection { id: u64, addr: SocketAddr },
+}
+
+/// A fallback transport for hosts that can't be reached via uPnP or hole punching (CGNAT, symmetric
+/// NAT): a single outbound control connection to a relay server is kept open, the relay assigns a
+/// public port, and each external connection to that port is multiplexed back over an additional
+/// outbound data connection keyed by a connection id. Exposed through the same
+/// `external()`/`is_forwarded()`/`Forwarded<T>` surface as `TcpListener::forwarded`, so callers can
+/// swap one for the other.
+pub struct RelayedListener {
+    incoming: Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl RelayedListener {
+    /// Connects to `relay_addr` and is assigned a public port plus a secret token; every data
+    /// connection opened afterwards proves it belongs to this client by echoing that token back.
+    /// The assigned port is not exposed: `Forwarded::external()` reports only the relay's IP address.
+    pub fn connect<A: ToSocketAddrs + Clone + Send + 'static>(relay_addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> {
+        let mut control = MessageStream::connect(relay_addr.clone())?;
+        control.send(RelayRequest::Control)?;
+
+        let (external, port, token) = loop {
+            match control.recv::<RelayReply>()? {
+                Some(RelayReply::Assigned { external, port, token }) => break (external, port, token),
+                Some(RelayReply::Connection { .. }) => continue,
+                None => sleep(Duration::from_millis(50)),
+            }
+        };
+
+        let state = Arc::new((Mutex::new(State::Running), Condvar::new()));
+        let (tx, rx) = mpsc::channel();
+
+        spawn({
+            let state = state.clone();
+            move || relay_client_loop(control, relay_addr, token, tx, state)
+        });
+
+        Ok(Forwarded {
+            inner: RelayedListener { incoming: rx },
+            external,
+            state
+        })
+    }
+
+    /// Works the same as `TcpListener::accept`, except the returned address is the external peer's
+    /// address as observed by the relay rather than a direct socket peer.
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.incoming.recv().map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "relay control connection closed"))
+    }
+}
+
+fn relay_client_loop<A: ToSocketAddrs + Clone>(
+    mut control: MessageStream,
+    relay_addr: A,
+    token: u64,
+    incoming: Sender<(TcpStream, SocketAddr)>,
+    state: Arc<(Mutex<State>, Condvar)>
+) {
+    loop {
+        if *state.0.lock().unwrap() == State::Terminate {
+            return;
+        }
+
+        let (id, addr) = match control.recv::<RelayReply>() {
+            Ok(Some(RelayReply::Connection { id, addr })) => (id, addr),
+            Ok(Some(RelayReply::Assigned { .. })) | Ok(None) => {
+                sleep(Duration::from_millis(50));
+                continue;
+            }
+            Err(_) => {
+                *state.0.lock().unwrap() = State::Error;
+                return;
+            }
+        };
+
+        let data = match TcpStream::connect(relay_addr.clone()) {
+            Ok(stream) => stream,
+            Err(_) => {
+                *state.0.lock().unwrap() = State::Error;
+                return;
+            }
+        };
+        let mut data = MessageStream::from_stream(data);
+        if data.send(RelayRequest::Data { id, token }).is_err() {
+            *state.0.lock().unwrap() = State::Error;
+            return;
+        }
+
+        // Nothing has been read from `data` yet at this point, so there's never buffered bytes to
+        // carry over here - unlike the relay server's own `into_inner` call, which does.
+        let (data, _) = data.into_inner();
+        if incoming.send((data, addr)).is_err() {
+            return;
+        }
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u64, (u64, Sender<(TcpStream, Vec<u8>)>)>>>;
+
+/// Reference relay server accept loop: listens on `addr`, hands each control connection a public
+/// port, and splices every subsequent external connection to that port onto the matching outbound
+/// data connection the client opens back in.  Control and data connections share the same listener
+/// and are told apart by the `RelayRequest` each one sends first. Runs until the listener errors.
+///
+/// `external` is the relay host's own publicly reachable address, handed to clients so
+/// `Forwarded::<RelayedListener>::external()` reports the relay's public IP rather than a local
+/// one; the assigned public port is sent to the client as well but not exposed by it. Unlike `TcpListener::forwarded` there's no uPnP gateway to ask, since a relay only works
+/// if it's already reachable from the internet, so the operator supplies it directly.
+pub fn relay_server<A: ToSocketAddrs>(addr: A, external: Ipv4Addr) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr)?;
+    let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+    let next_id = Arc::new(AtomicU64::new(0));
+
+    loop {
+        let (stream, _addr) = listener.accept()?;
+        let pending = pending.clone();
+        let next_id = next_id.clone();
+        spawn(move || {
+            if let Err(err) = handle_relay_connection(stream, pending, next_id, external) {
+                log::warn!("relay connection handler failed: {err}");
+            }
+        });
+    }
+}
+
+fn handle_relay_connection(stream: TcpStream, pending: Pending, next_id: Arc<AtomicU64>, external: Ipv4Addr) -> Result<(), Box<dyn Error>> {
+    let mut stream = MessageStream::from_stream(stream);
+    let request: RelayRequest = loop {
+        if let Some(request) = stream.recv()? {
+            break request;
+        }
+    };
+
+    match request {
+        RelayRequest::Control => handle_control_connection(stream, pending, next_id, external),
+        RelayRequest::Data { id, token } => {
+            // Only hand off the connection if `token` matches the secret the owning `Control`
+            // connection was issued - otherwise leave the entry in place and drop this connection,
+            // so a third party guessing/incrementing `id` can't race the real client for it.
+            let mut pending = pending.lock().unwrap();
+            if pending.get(&id).is_some_and(|(expected, _)| *expected == token) {
+                let (_, tx) = pending.remove(&id).unwrap();
+                drop(pending);
+                let _ = tx.send(stream.into_inner());
+            } else {
+                log::warn!("relay data connection for id {id} presented a bad token, dropping");
+            }
+            Ok(())
+        }
+    }
+}
+
+fn handle_control_connection(mut control: MessageStream, pending: Pending, next_id: Arc<AtomicU64>, external: Ipv4Addr) -> Result<(), Box<dyn Error>> {
+    let public_listener = TcpListener::bind("0.0.0.0:0")?;
+    let port = public_listener.local_addr()?.port();
+    let token: u64 = rand::thread_rng().gen();
+
+    control.send(RelayReply::Assigned { external, port, token })?;
+
+    loop {
+        let (external, addr) = public_listener.accept()?;
+        let id = next_id.fetch_add(1, Ordering::Relaxed);
+
+        let (tx, rx) = mpsc::channel();
+        pending.lock().unwrap().insert(id, (token, tx));
+
+        control.send(RelayReply::Connection { id, addr })?;
+
+        let pending = pending.clone();
+        spawn(move || {
+            if let Ok((data, leftover)) = rx.recv_timeout(Duration::from_secs(10)) {
+                let _ = splice(external, data, leftover);
+            }
+            pending.lock().unwrap().remove(&id);
+        });
+    }
+}
+
+fn splice(mut a: TcpStream, mut b: TcpStream, b_leftover: Vec<u8>) -> io::Result<()> {
+    // `b`'s MessageStream may have already buffered application bytes sent right after the
+    // RelayRequest::Data header arrived, e.g. in the same segment. Those bytes belong to `a`, the
+    // real peer, and must go out before the raw bidirectional copy starts.
+    if !b_leftover.is_empty() {
+        a.write_all(&b_leftover)?;
+    }
+    let mut a2 = a.try_clone()?;
+    let mut b2 = b.try_clone()?;
+    let forward = spawn(move || io::copy(&mut a2, &mut b2));
+    io::copy(&mut b, &mut a)?;
+    let _ = forward.join();
+    Ok(())
+}
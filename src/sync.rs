@@ -1,52 +1,184 @@
 use super::forward::*;
+use super::codec::{Bincode, Codec, Rkyv, RkyvRef};
 
 use std::{
     error::Error,
     io::{self, ErrorKind, Read, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, ToSocketAddrs},
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
     sync::{Arc, Mutex, Condvar},
-    thread::spawn
+    thread::spawn,
+    time::{Duration, Instant}
 };
 use igd::{search_gateway, PortMappingProtocol, SearchOptions};
 use local_ip_address::local_ip;
 use serde::{de::DeserializeOwned, Serialize};
 use bincode;
 
+/// How long `redial` waits for a reconnect attempt before giving up, so a dead/unreachable peer
+/// can't stall `recv`'s documented non-blocking contract for the OS's SYN-retry timeout.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// A wrapper for `TcpStream` that allows to simply send and receive structs which implement `serde::{Serialize, Deserialize}`.
-pub struct MessageStream {
+/// Parameterized by a `Codec` controlling how payloads are encoded; defaults to `Bincode`, matching
+/// the wire format `netu` has always used.
+pub struct MessageStream<C = Bincode> {
     inner: TcpStream,
     offset: usize,
-    buffer: Vec<u8>
+    buffer: Vec<u8>,
+    referenced: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    reconnect: Option<SocketAddr>,
+    generation: u64,
+    codec: PhantomData<C>
 }
 
-impl MessageStream {
+impl MessageStream<Bincode> {
+    pub(crate) fn from_stream(stream: TcpStream) -> Self {
+        MessageStream {
+            inner: stream,
+            offset: 0,
+            buffer: vec![0u8; 1024],
+            referenced: false,
+            read_timeout: None,
+            write_timeout: None,
+            reconnect: None,
+            generation: 0,
+            codec: PhantomData
+        }
+    }
     /// Works the same as `TcpStream::connect`.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
         TcpStream::connect(addr).map(|stream| {
             stream.set_nonblocking(true).unwrap();
-            MessageStream {
-                inner: stream,
-                offset: 0,
-                buffer: vec![0u8; 1024]
-            }
+            Self::from_stream(stream)
         })
     }
-    /// Send a type that implements `serde::Serialize`.
-    pub fn send<M: Serialize>(&mut self, message: M) -> Result<(), Box<dyn Error>> {
-        let raw = bincode::serialize(&message)?;
-        let header = (8 + raw.len() as u64).to_be_bytes();
-        self.inner.write_all(&header)?;
-        self.inner.write_all(&raw)?;
+    /// Like `connect`, but remembers `addr` so a read/write error transparently redials instead of
+    /// surfacing as a hard error, resyncing the framing by discarding any partial message. Use
+    /// `generation` to notice a reconnect happened so in-flight application messages can be resent.
+    pub fn connect_reconnecting<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let target = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+        })?;
+        let mut stream = Self::connect(target)?;
+        stream.reconnect = Some(target);
+        Ok(stream)
+    }
+}
+
+impl<C> MessageStream<C> {
+    /// Unwraps into the raw `TcpStream`, along with any bytes already read into the internal
+    /// buffer but not yet consumed by `recv`/`recv_ref` (e.g. application bytes that happened to
+    /// arrive in the same segment as whatever `recv` last decoded). Callers that hand the raw
+    /// stream off to something else must treat these as the first bytes of the connection.
+    pub(crate) fn into_inner(mut self) -> (TcpStream, Vec<u8>) {
+        self.buffer.truncate(self.offset);
+        (self.inner, self.buffer)
+    }
+    /// Swaps the `Codec` this stream uses, e.g. `MessageStream::connect(addr)?.with_codec::<Rkyv>()`.
+    pub fn with_codec<C2>(self) -> MessageStream<C2> {
+        MessageStream {
+            inner: self.inner,
+            offset: self.offset,
+            buffer: self.buffer,
+            referenced: self.referenced,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            reconnect: self.reconnect,
+            generation: self.generation,
+            codec: PhantomData
+        }
+    }
+    /// Bounds how long `recv_blocking` waits for a full message. `None` (the default) waits forever.
+    /// Has no effect on `recv`, which never blocks.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+    /// Forwards to the underlying socket, mirroring `TcpStream::set_write_timeout`. Remembered so
+    /// it survives a transparent redial in reconnecting mode, which otherwise hands `recv`/`send` a
+    /// brand-new `TcpStream` with no timeout configured at all.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)?;
+        self.write_timeout = timeout;
+        Ok(())
+    }
+    /// Monotonically increasing counter bumped every time a dropped/half-broken connection is
+    /// transparently redialed in reconnecting mode (see `connect_reconnecting`). Always `0` for a
+    /// stream that isn't in reconnecting mode.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    fn redial(&mut self) -> io::Result<()> {
+        let addr = self.reconnect.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "reconnecting mode not enabled")
+        })?;
+        // Bounded so a dead/unreachable peer can't stall recv()'s non-blocking contract for the
+        // OS's SYN-retry timeout.
+        let stream = TcpStream::connect_timeout(&addr, RECONNECT_TIMEOUT)?;
+        stream.set_nonblocking(true)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        self.inner = stream;
+        self.offset = 0;
+        self.buffer.clear();
+        self.buffer.resize(1024, 0);
+        self.generation += 1;
         Ok(())
     }
-    /// Receieve a type that implements `serde::Deserialize`.
+    /// Send a type that this stream's `Codec` can encode.
+    pub fn send<M>(&mut self, message: M) -> Result<(), Box<dyn Error>> where C: Codec<M> {
+        let raw = C::encode(&message).map_err(|err| Box::new(err) as Box<dyn Error>)?;
+        let header = (8 + raw.len() as u64).to_be_bytes();
+        match self.write_frame(&header, &raw) {
+            Ok(()) => Ok(()),
+            // An ordinary full-send-buffer WouldBlock on this non-blocking socket is just
+            // backpressure, not a dead connection - don't force a redial over it.
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(Box::new(err)),
+            Err(_) if self.reconnect.is_some() => {
+                self.redial()?;
+                self.write_frame(&header, &raw)?;
+                Ok(())
+            },
+            Err(err) => Err(Box::new(err))
+        }
+    }
+    fn write_frame(&mut self, header: &[u8], raw: &[u8]) -> io::Result<()> {
+        self.inner.write_all(header)?;
+        self.inner.write_all(raw)
+    }
+    /// Drops the bytes behind a reference handed out by `recv_ref`, once the caller is back for
+    /// more - mirrors `AsyncMessageStream`'s handling of the same borrow-until-next-call contract.
+    fn drain_referenced(&mut self) {
+        if self.referenced {
+            let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
+            self.buffer.drain(0..size);
+            self.offset -= size;
+            self.referenced = false;
+        }
+    }
+    /// Receieve a type that this stream's `Codec` can decode.
     /// This function is non-blocking and has internal buffering.
-    pub fn recv<M: DeserializeOwned>(&mut self) -> Result<Option<M>, Box<dyn Error>> {
+    pub fn recv<M>(&mut self) -> Result<Option<M>, Box<dyn Error>> where C: Codec<M> {
+        self.drain_referenced();
+
         let n = match self.inner.read(&mut self.buffer[self.offset..]) {
+            // A graceful close (peer FIN) surfaces as Ok(0), not an Err, but is just as dead a
+            // connection as a reset.
+            Ok(0) if self.reconnect.is_some() => {
+                self.redial()?;
+                return Ok(None);
+            },
+            Ok(0) => return Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))),
             Ok(n) => n,
             Err(err) if err.kind() == ErrorKind::WouldBlock => {
                 return Ok(None)
             },
+            Err(_) if self.reconnect.is_some() => {
+                self.redial()?;
+                return Ok(None);
+            },
             err => err?
         };
         self.offset += n;
@@ -57,10 +189,19 @@ impl MessageStream {
 
             // If the buffer is full it most likely means that there's more waiting already
             let n = match self.inner.read(&mut self.buffer[self.offset..]) {
+                Ok(0) if self.reconnect.is_some() => {
+                    self.redial()?;
+                    return Ok(None);
+                },
+                Ok(0) => return Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))),
                 Ok(n) => n,
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     break
                 },
+                Err(_) if self.reconnect.is_some() => {
+                    self.redial()?;
+                    return Ok(None);
+                },
                 err => err?
             };
             self.offset += n;
@@ -69,7 +210,7 @@ impl MessageStream {
         if self.offset > 8 {
             let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
             if self.offset >= size {
-                let message: M = bincode::deserialize(&self.buffer[8..size])?;
+                let message: M = C::decode(&self.buffer[8..size]).map_err(|err| Box::new(err) as Box<dyn Error>)?;
                 self.buffer.drain(0..size);
                 self.offset -= size;
                 return Ok(Some(message));
@@ -78,8 +219,202 @@ impl MessageStream {
 
         Ok(None)
     }
+    /// Like `recv`, but blocks until a full message arrives instead of returning `Ok(None)`.
+    /// Bounded by `set_read_timeout`; if it elapses first this returns `RecvError::TimedOut`
+    /// rather than leaving the caller to guess whether `None` meant "nothing yet" or "gone".
+    pub fn recv_blocking<M>(&mut self) -> Result<M, RecvError> where C: Codec<M> {
+        self.drain_referenced();
+        self.inner.set_nonblocking(false).map_err(RecvError::Io)?;
+        self.inner.set_read_timeout(self.read_timeout).map_err(RecvError::Io)?;
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        let result = (|| loop {
+            // recv()'s over-read can already have buffered a full message (or more) from a
+            // previous call; serve that before blocking on a read that may never come.
+            if self.offset > 8 {
+                let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
+                if self.offset >= size {
+                    let message: M = C::decode(&self.buffer[8..size]).map_err(|err| RecvError::Decode(Box::new(err)))?;
+                    self.buffer.drain(0..size);
+                    self.offset -= size;
+                    return Ok(message);
+                }
+            }
+
+            // Each read only gets what is left of the overall timeout.
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(RecvError::TimedOut);
+                }
+                self.inner.set_read_timeout(Some(remaining)).map_err(RecvError::Io)?;
+            }
+
+            let n = match self.inner.read(&mut self.buffer[self.offset..]) {
+                // A graceful close (peer FIN) surfaces as Ok(0), not an Err, but is just as dead a
+                // connection as a reset; left unhandled this busy-spins forever re-reading Ok(0).
+                Ok(0) if self.reconnect.is_some() => {
+                    self.redial().map_err(RecvError::Io)?;
+                    self.inner.set_nonblocking(false).map_err(RecvError::Io)?;
+                    self.inner.set_read_timeout(self.read_timeout).map_err(RecvError::Io)?;
+                    continue;
+                },
+                Ok(0) => return Err(RecvError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))),
+                Ok(n) => n,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(RecvError::TimedOut)
+                },
+                Err(_) if self.reconnect.is_some() => {
+                    self.redial().map_err(RecvError::Io)?;
+                    self.inner.set_nonblocking(false).map_err(RecvError::Io)?;
+                    self.inner.set_read_timeout(self.read_timeout).map_err(RecvError::Io)?;
+                    continue;
+                },
+                Err(err) => return Err(RecvError::Io(err))
+            };
+            self.offset += n;
+
+            while self.offset == self.buffer.len() {
+                self.buffer.extend(std::iter::repeat(0).take(self.buffer.len() * 2));
+                // Don't block for more bytes when a full message is already buffered.
+                if u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize <= self.offset {
+                    break;
+                }
+                let n = match self.inner.read(&mut self.buffer[self.offset..]) {
+                    Ok(0) if self.reconnect.is_some() => {
+                        self.redial().map_err(RecvError::Io)?;
+                        self.inner.set_nonblocking(false).map_err(RecvError::Io)?;
+                        self.inner.set_read_timeout(self.read_timeout).map_err(RecvError::Io)?;
+                        continue;
+                    },
+                    Ok(0) => return Err(RecvError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))),
+                    Ok(n) => n,
+                    Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        return Err(RecvError::TimedOut)
+                    },
+                    Err(_) if self.reconnect.is_some() => {
+                        self.redial().map_err(RecvError::Io)?;
+                        self.inner.set_nonblocking(false).map_err(RecvError::Io)?;
+                        self.inner.set_read_timeout(self.read_timeout).map_err(RecvError::Io)?;
+                        continue;
+                    },
+                    Err(err) => return Err(RecvError::Io(err))
+                };
+                self.offset += n;
+            }
+        })();
+
+        let _ = self.inner.set_nonblocking(true);
+        result
+    }
+}
+
+impl MessageStream<Rkyv> {
+    /// Receive a type validated in place against the internal buffer, with no allocation or copy.
+    /// The returned reference borrows `self`: it stays valid until the next `recv`/`recv_ref` call,
+    /// which is what drops and replaces the bytes it points into. Non-blocking, like `recv`.
+    pub fn recv_ref<M: RkyvRef>(&mut self) -> Result<Option<&rkyv::Archived<M>>, Box<dyn Error>> {
+        self.drain_referenced();
+
+        let n = match self.inner.read(&mut self.buffer[self.offset..]) {
+            Ok(0) if self.reconnect.is_some() => {
+                self.redial()?;
+                return Ok(None);
+            },
+            Ok(0) => return Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))),
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                return Ok(None)
+            },
+            Err(_) if self.reconnect.is_some() => {
+                self.redial()?;
+                return Ok(None);
+            },
+            err => err?
+        };
+        self.offset += n;
+
+        // Extend buffer while it's full
+        while self.offset == self.buffer.len() {
+            self.buffer.extend(std::iter::repeat(0).take(self.buffer.len() * 2));
+
+            // If the buffer is full it most likely means that there's more waiting already
+            let n = match self.inner.read(&mut self.buffer[self.offset..]) {
+                Ok(0) if self.reconnect.is_some() => {
+                    self.redial()?;
+                    return Ok(None);
+                },
+                Ok(0) => return Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))),
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break
+                },
+                Err(_) if self.reconnect.is_some() => {
+                    self.redial()?;
+                    return Ok(None);
+                },
+                err => err?
+            };
+            self.offset += n;
+        }
+
+        if self.offset > 8 {
+            let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
+            if self.offset >= size {
+                let archived = rkyv::check_archived_root::<M>(&self.buffer[8..size])
+                    .map_err(|_| Box::new(RkyvValidationError) as Box<dyn Error>)?;
+                self.referenced = true;
+                return Ok(Some(archived));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+struct RkyvValidationError;
+
+impl std::fmt::Display for RkyvValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "received payload failed rkyv validation")
+    }
+}
+
+impl Error for RkyvValidationError {}
+
+/// Error returned by `MessageStream::recv_blocking`.
+#[derive(Debug)]
+pub enum RecvError {
+    Io(io::Error),
+    Decode(Box<dyn Error>),
+    TimedOut
+}
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
+impl Error for RecvError {}
+
+/// Returned by `MessageDatagram::recv`/`AsyncMessageDatagram::recv` when a datagram's length
+/// header claims more bytes than were received, which is what a message too large for the fixed
+/// 1024-byte receive buffer looks like. Since UDP delivers a datagram whole or not at all, this
+/// means either the sender's message was too large to ever be decoded over this transport, or the
+/// datagram is corrupt/malicious - either way, distinct from "nothing has arrived yet".
+#[derive(Debug)]
+pub struct DatagramTooLarge;
+
+impl std::fmt::Display for DatagramTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "received datagram's length header exceeds the bytes received")
+    }
+}
+
+impl Error for DatagramTooLarge {}
+
 pub trait TcpListenerExt {
     fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> where Self: Sized;
     fn messenger(&self) -> io::Result<(MessageStream, SocketAddr)>;
@@ -89,7 +424,7 @@ impl TcpListenerExt for TcpListener {
     /// Works the same as `TcpListener::bind` but also spawns a thread that periodically requests router to uPnP forward specified port.
     fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> {
         let listener = TcpListener::bind(addr)?;
-        let (external, state) = forward(listener.local_addr().unwrap().port())?;
+        let (external, state) = forward(listener.local_addr().unwrap().port(), PortMappingProtocol::TCP)?;
         Ok(Forwarded {
             inner: listener,
             external,
@@ -98,18 +433,91 @@ impl TcpListenerExt for TcpListener {
     }
     /// Works the same as `TcpListener::accept` but returns a `MessageStream` instead of `TcpStream`.
     fn messenger(&self) -> io::Result<(MessageStream, SocketAddr)> {
-        self.accept().map(|(stream, addr)| (
-            MessageStream {
-                inner: stream,
-                offset: 0,
-                buffer: vec![0u8; 1024]
+        self.accept().map(|(stream, addr)| (MessageStream::from_stream(stream), addr))
+    }
+}
+
+pub trait UdpSocketExt {
+    fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> where Self: Sized;
+}
+
+impl UdpSocketExt for UdpSocket {
+    /// Works the same as `UdpSocket::bind` but also spawns a thread that periodically requests router to uPnP forward specified port.
+    fn forwarded<A: ToSocketAddrs>(addr: A) -> Result<Forwarded<Self>, Box<dyn Error>> {
+        let socket = UdpSocket::bind(addr)?;
+        let (external, state) = forward(socket.local_addr().unwrap().port(), PortMappingProtocol::UDP)?;
+        Ok(Forwarded {
+            inner: socket,
+            external,
+            state
+        })
+    }
+}
+
+/// A wrapper for `UdpSocket` that allows to simply send and receive structs which implement `serde::{Serialize, Deserialize}`.
+/// Unlike `MessageStream` there is no internal buffering across calls: each datagram carries exactly one framed message.
+/// The receive buffer is a fixed 1024 bytes and never grows, so an encoded message (8-byte header
+/// included) larger than that can never be received - see `recv`.
+pub struct MessageDatagram {
+    inner: UdpSocket,
+    buffer: Vec<u8>
+}
+
+impl MessageDatagram {
+    /// Binds a socket and connects it to `addr`, so `send`/`recv` can be used without specifying a peer on every call.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(MessageDatagram {
+            inner: socket,
+            buffer: vec![0u8; 1024]
+        })
+    }
+    /// Send a type that implements `serde::Serialize` as a single datagram. `message` must encode,
+    /// header included, to 1024 bytes or less - `recv` can't ever deliver anything larger.
+    pub fn send<M: Serialize>(&mut self, message: M) -> Result<(), Box<dyn Error>> {
+        let raw = bincode::serialize(&message)?;
+        let mut packet = (8 + raw.len() as u64).to_be_bytes().to_vec();
+        packet.extend_from_slice(&raw);
+        self.inner.send(&packet)?;
+        Ok(())
+    }
+    /// Receive a type that implements `serde::Deserialize`.
+    /// This function is non-blocking: it returns `Ok(None)` when no datagram is currently available.
+    /// Returns `Err(DatagramTooLarge)` if a datagram's length header claims more bytes than were
+    /// received (as for any message over the 1024-byte buffer), rather than silently dropping it
+    /// as if nothing had arrived.
+    pub fn recv<M: DeserializeOwned>(&mut self) -> Result<Option<M>, Box<dyn Error>> {
+        let n = match self.inner.recv(&mut self.buffer) {
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                return Ok(None)
             },
-            addr
-        ))
+            err => err?
+        };
+
+        if n < 8 {
+            return Ok(None);
+        }
+
+        // A malformed/garbage datagram (e.g. a bare probe on the newly-forwarded port) can claim
+        // any length in its header; reject anything that doesn't actually fit what we received
+        // instead of indexing blindly.
+        let size = u64::from_be_bytes(self.buffer[0..8].try_into().unwrap()) as usize;
+        if size < 8 {
+            return Ok(None);
+        }
+        if size > n {
+            return Err(Box::new(DatagramTooLarge));
+        }
+
+        let message: M = bincode::deserialize(&self.buffer[8..size])?;
+        Ok(Some(message))
     }
 }
 
-fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dyn Error>>  {
+fn forward(port: u16, protocol: PortMappingProtocol) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dyn Error>>  {
     let ip = local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
 
     let gateway = match search_gateway(SearchOptions::default()) {
@@ -132,7 +540,7 @@ fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dy
 
     gateway
     .add_port(
-        PortMappingProtocol::TCP,
+        protocol,
         port,
         SocketAddrV4::new(ip, port),
         LEASE.as_secs() as u32 + 1,
@@ -147,7 +555,7 @@ fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dy
             loop {
                 if let Err(_err) = gateway
                     .add_port(
-                        PortMappingProtocol::TCP,
+                        protocol,
                         port,
                         SocketAddrV4::new(ip, port),
                         LEASE.as_secs() as u32 + 1,
@@ -170,7 +578,7 @@ fn forward(port: u16) -> Result<(Ipv4Addr, Arc<(Mutex<State>, Condvar)>), Box<dy
                     break;
                 }
             }
-            let _ = gateway.remove_port(PortMappingProtocol::TCP, port);
+            let _ = gateway.remove_port(protocol, port);
         }
     });
 